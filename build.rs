@@ -0,0 +1,34 @@
+// Copyright 2021 Developers of Pyroscope.
+
+// Licensed under the Apache License, Version 2.0 <LICENSE or
+// https://www.apache.org/licenses/LICENSE-2.0>. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::env;
+use std::process::Command;
+
+/// Capture the build environment for the build_env_tags() option
+fn main() {
+    // Target triple, profile (debug/release) and optimization level
+    for (name, var) in [
+        ("PYROSCOPE_BUILD_TARGET", "TARGET"),
+        ("PYROSCOPE_BUILD_PROFILE", "PROFILE"),
+        ("PYROSCOPE_BUILD_OPT_LEVEL", "OPT_LEVEL"),
+    ] {
+        let value = env::var(var).unwrap_or_else(|_| String::from("unknown"));
+        println!("cargo:rustc-env={}={}", name, value);
+    }
+
+    // rustc version, e.g. `1.57.0` from `rustc 1.57.0 (f1edd0429 2021-11-29)`
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
+    let version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .and_then(|output| output.split_whitespace().nth(1).map(String::from))
+        .unwrap_or_else(|| String::from("unknown"));
+    println!("cargo:rustc-env=PYROSCOPE_RUSTC_VERSION={}", version);
+
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -26,6 +26,19 @@ fn main() -> Result<()> {
     let report = backend.report()?;
     println!("{}", std::str::from_utf8(&report).unwrap());
 
+    // report() does not reset the samples: this report includes the first
+    // fibonacci() call as well
+    fibonacci(40);
+    let report = backend.report()?;
+    println!("{}", std::str::from_utf8(&report).unwrap());
+
+    // Restart the backend to collect the samples of a new window only
+    backend.stop()?;
+    backend.start()?;
+    fibonacci(40);
+    let report = backend.report()?;
+    println!("{}", std::str::from_utf8(&report).unwrap());
+
     backend.stop()?;
 
     Ok(())
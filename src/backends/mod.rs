@@ -7,28 +7,240 @@
 use crate::Result;
 
 use std::fmt::Debug;
+use std::future::Future;
 
-/// Backend State 
-#[derive(Clone, Copy, PartialEq)]
+/// Backend State
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub enum State {
+    #[default]
     Uninitialized,
     Ready,
     Running,
 }
 
-impl Default for State {
-    fn default() -> Self {
-        State::Uninitialized
+/// Aggregation function the server applies to the samples of a profile
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Aggregation {
+    /// Samples are added up, e.g. CPU time
+    #[default]
+    Sum,
+    /// Samples are averaged, e.g. gauges
+    Average,
+    /// Only the latest samples are kept
+    Last,
+}
+
+impl Aggregation {
+    /// Value of the `aggregationType` query parameter
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Aggregation::Sum => "sum",
+            Aggregation::Average => "average",
+            Aggregation::Last => "last",
+        }
     }
 }
 
 /// Backend Trait
+///
+/// The agent drives any backend the same way: initialize() with the sample
+/// rate when the agent is built, start() and stop() with the agent, and
+/// report() at the end of every window. A report is a profile in the folded
+/// format (`frame;frame;frame count` lines), uploaded as is.
+///
+/// report() returns the samples collected since the last start() and must
+/// not reset them: two calls without a restart return growing totals. The
+/// agent restarts the backend (stop() then start()) after every report to
+/// upload per-window profiles with Accumulation::Delta, the default, and
+/// keeps it running with Accumulation::Cumulative. Backends written for
+/// versions where report() restarted the profiler itself must drop that
+/// restart, or Cumulative reports only cover the last window.
+///
+/// The count of a stack is its weight, not necessarily a number of samples:
+/// the pprof backend counts every sample once, a wall-clock backend may
+/// weigh each sample by the time it covers (see ProfileMerger::add_sample).
+/// The server draws every stack with a width proportional to its weight,
+/// relative to the total weight of the profile, and converts weights to
+/// durations with the sample rate of the upload: a weight of 1 is one
+/// sampling period, 10ms at the default 100Hz. Weights in other units
+/// render with correct proportions but wrong durations; pick the weight
+/// unit accordingly, or declare a non-additive aggregation().
 pub trait Backend: Send + Debug {
     fn get_state(&self) -> State;
     fn initialize(&mut self, sample_rate: i32) -> Result<()>;
     fn start(&mut self) -> Result<()>;
     fn stop(&mut self) -> Result<()>;
+    /// Samples collected since the last start(), without resetting them
     fn report(&mut self) -> Result<Vec<u8>>;
+
+    /// Name of the profiler, uploaded as the spyName of the reports
+    fn spy_name(&self) -> &'static str {
+        "pprof-rs"
+    }
+
+    /// Aggregation of the samples, uploaded as the aggregationType of the
+    /// reports. Backends of non-additive metrics should not use the default,
+    /// Sum.
+    fn aggregation(&self) -> Aggregation {
+        Aggregation::Sum
+    }
+
+    /// Number of threads left out of the last report, if the backend limits
+    /// them
+    fn unsampled_threads(&self) -> usize {
+        0
+    }
+}
+
+/// Boxed backends, e.g. chosen at runtime
+impl<T: Backend + ?Sized> Backend for Box<T> {
+    fn get_state(&self) -> State {
+        (**self).get_state()
+    }
+
+    fn initialize(&mut self, sample_rate: i32) -> Result<()> {
+        (**self).initialize(sample_rate)
+    }
+
+    fn start(&mut self) -> Result<()> {
+        (**self).start()
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        (**self).stop()
+    }
+
+    fn report(&mut self) -> Result<Vec<u8>> {
+        (**self).report()
+    }
+
+    fn spy_name(&self) -> &'static str {
+        (**self).spy_name()
+    }
+
+    fn aggregation(&self) -> Aggregation {
+        (**self).aggregation()
+    }
+
+    fn unsampled_threads(&self) -> usize {
+        (**self).unsampled_threads()
+    }
 }
 
-pub mod pprof;
+/// Profile a future: start the backend, await the future, and return its
+/// output with the report of the samples taken meanwhile. The backend must
+/// be initialized (State::Ready), and is Ready again when the function
+/// returns, including when the future panics or when the returned future is
+/// dropped before completion (e.g. by a timeout).
+///
+/// This is the async counterpart of calling start(), report() and stop() on
+/// a backend around synchronous code (see `examples/backend-pprof.rs`). It
+/// does not need an agent nor a server, and does not depend on an async
+/// runtime. Sampling is process-wide: the report covers every thread, not
+/// only the one polling the future, so other work running concurrently
+/// shows up in the report too.
+///
+/// ```ignore
+/// let mut backend = Pprof::default();
+/// backend.initialize(100)?;
+/// let (response, report) = profile_until(&mut backend, handle(request)).await?;
+/// ```
+pub async fn profile_until<B, F>(backend: &mut B, future: F) -> Result<(F::Output, Vec<u8>)>
+where
+    B: Backend + ?Sized,
+    F: Future,
+{
+    backend.start()?;
+    let guard = StopGuard(backend);
+
+    let output = future.await;
+    let report = guard.0.report()?;
+    guard.0.stop()?;
+
+    Ok((output, report))
+}
+
+/// Stop a running backend when dropped
+struct StopGuard<'a, B: Backend + ?Sized>(&'a mut B);
+
+impl<B: Backend + ?Sized> Drop for StopGuard<'_, B> {
+    fn drop(&mut self) {
+        if self.0.get_state() == State::Running {
+            // Nowhere to report the error, the backend is left Running
+            let _ = self.0.stop();
+        }
+    }
+}
+
+#[cfg(unix)] pub mod pprof;
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::time::Duration;
+
+    use crate::backends::{profile_until, Backend, State};
+
+    /// Backend counting the times it was started
+    #[derive(Debug, Default)]
+    struct CountingBackend {
+        state: State,
+        starts: u32,
+    }
+
+    impl Backend for CountingBackend {
+        fn get_state(&self) -> State {
+            self.state
+        }
+
+        fn initialize(&mut self, _sample_rate: i32) -> crate::Result<()> {
+            self.state = State::Ready;
+            Ok(())
+        }
+
+        fn start(&mut self) -> crate::Result<()> {
+            self.state = State::Running;
+            self.starts += 1;
+            Ok(())
+        }
+
+        fn stop(&mut self) -> crate::Result<()> {
+            self.state = State::Ready;
+            Ok(())
+        }
+
+        fn report(&mut self) -> crate::Result<Vec<u8>> {
+            Ok(format!("main;fn {}\n", self.starts).into_bytes())
+        }
+    }
+
+    #[test]
+    fn profile_future() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut backend = CountingBackend::default();
+        backend.initialize(100).unwrap();
+
+        let (output, report) = runtime
+            .block_on(profile_until(&mut backend, async { 42 }))
+            .unwrap();
+        assert_eq!((output, report), (42, b"main;fn 1\n".to_vec()));
+        assert_eq!(backend.get_state(), State::Ready);
+
+        // Cancelled
+        let cancelled = runtime.block_on(async {
+            let future = profile_until(&mut backend, std::future::pending::<()>());
+            tokio::time::timeout(Duration::from_millis(10), future).await
+        });
+        assert!(cancelled.is_err());
+        assert_eq!(backend.get_state(), State::Ready);
+
+        // Panicked
+        let panicked = catch_unwind(AssertUnwindSafe(|| {
+            runtime.block_on(profile_until(&mut backend, async {
+                panic!("handler failed")
+            }))
+        }));
+        assert!(panicked.is_err());
+        assert_eq!((backend.get_state(), backend.starts), (State::Ready, 3));
+    }
+}
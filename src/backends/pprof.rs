@@ -4,17 +4,205 @@
 // https://www.apache.org/licenses/LICENSE-2.0>. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use std::collections::BTreeMap;
+
 use pprof::{ProfilerGuard, ProfilerGuardBuilder, Report};
 
 use crate::backends::Backend;
 use crate::backends::State;
-use crate::Result;
+use crate::{PyroscopeError, Result};
+
+/// Frame replacing collapsed frames outside of the focus prefixes
+pub const EXTERNAL_FRAME: &str = "[external]";
+
+/// Frame marking the end of a stack truncated by max_single_stack_depth
+pub const TRUNCATED_FRAME: &str = "[truncated]";
+
+/// Marker appended to symbols shortened by max_symbol_length
+pub const SHORTENED_SYMBOL: &str = "...";
 
-#[derive(Default)]
+/// Stack gathering the samples of the stacks beyond max_distinct_stacks
+pub const OTHER_STACK: &str = "[other]";
+
+/// Root frame gathering the stacks of the threads beyond max_profiled_threads
+pub const OTHER_THREADS: &str = "[other threads]";
+
+/// pprof-rs backend
+///
+/// Frames are symbolized by pprof-rs when the report is built, with the
+/// `backtrace` crate reading the DWARF debug information of the running
+/// binary. The symbol source can't be overridden: pprof-rs resolves
+/// addresses internally and does not expose the raw addresses of a report.
+/// Stripped binaries can still be symbolized on Linux by installing their
+/// separate debug file where `backtrace` looks for it: under
+/// `/usr/lib/debug/.build-id/xx/yyyy.debug` (GNU build ID) or next to the
+/// binary as referenced by its `.gnu_debuglink` section. Frames that can't be
+/// resolved are named `Unknow` (sic) by pprof-rs.
 pub struct Pprof<'a> {
     inner_builder: Option<ProfilerGuardBuilder>,
     guard: Option<ProfilerGuard<'a>>,
     state: State,
+    /// Restart system calls interrupted by SIGPROF
+    restart_syscalls: bool,
+    /// Symbol prefixes of the frames to keep
+    focus_prefixes: Vec<String>,
+    /// Maximum number of distinct stacks in a report
+    max_distinct_stacks: Option<usize>,
+    /// Maximum depth of a single stack
+    max_single_stack_depth: Option<usize>,
+    /// Maximum length of a symbol name
+    max_symbol_length: Option<usize>,
+    /// Maximum number of threads in a report
+    max_profiled_threads: Option<usize>,
+    /// Expand inlined functions into frames of their own
+    preserve_inlined: bool,
+    /// Number of threads gathered into OTHER_THREADS in the last report
+    unsampled_threads: usize,
+}
+
+impl Default for Pprof<'_> {
+    fn default() -> Self {
+        Self {
+            inner_builder: None,
+            guard: None,
+            state: State::default(),
+            restart_syscalls: true,
+            focus_prefixes: Vec::new(),
+            max_distinct_stacks: None,
+            max_single_stack_depth: None,
+            max_symbol_length: None,
+            max_profiled_threads: None,
+            preserve_inlined: true,
+            unsampled_threads: 0,
+        }
+    }
+}
+
+impl Pprof<'_> {
+    /// Set whether system calls interrupted by the sampling signal (SIGPROF)
+    /// are restarted (SA_RESTART). When disabled, blocking system calls in
+    /// the profiled application may fail with EINTR. Default is true.
+    ///
+    /// The flag is applied right after pprof installs its signal handler, so
+    /// the first samples of a window may still interrupt system calls.
+    pub fn restart_syscalls(self, restart_syscalls: bool) -> Self {
+        Self {
+            restart_syscalls,
+            ..self
+        }
+    }
+
+    /// Keep only the frames whose symbol starts with one of the prefixes,
+    /// e.g. `&["myapp::"]`. Every run of consecutive frames outside of the
+    /// prefixes is collapsed into a single `[external]` frame, which keeps
+    /// the sample count of the stacks going through it. The root frame
+    /// (thread name) is always kept. Default is to keep all frames.
+    pub fn focus_prefixes(self, prefixes: &[&str]) -> Self {
+        Self {
+            focus_prefixes: prefixes.iter().map(|prefix| prefix.to_string()).collect(),
+            ..self
+        }
+    }
+
+    /// Keep only the `max` heaviest stacks (by sample count) of a report.
+    /// The samples of all other stacks are summed into a single `[other]`
+    /// stack, so the report has at most `max + 1` lines and the total sample
+    /// count is unchanged. Default is no limit.
+    pub fn max_distinct_stacks(self, max: usize) -> Self {
+        Self {
+            max_distinct_stacks: Some(max),
+            ..self
+        }
+    }
+
+    /// Truncate stacks deeper than `max` frames (not counting the thread
+    /// name). The `max` frames closest to the root are kept and a
+    /// `[truncated]` frame is appended in place of the deeper ones, so the
+    /// samples still show up under their callers. Other stacks are left
+    /// intact. Default is no limit.
+    pub fn max_single_stack_depth(self, max: usize) -> Self {
+        Self {
+            max_single_stack_depth: Some(max),
+            ..self
+        }
+    }
+
+    /// Shorten symbol names longer than `max` characters, e.g. deeply
+    /// generic monomorphized functions, to their first `max` characters
+    /// followed by `...`. Symbols are shortened after demangling and after
+    /// focus_prefixes is applied. Distinct symbols sharing the same first
+    /// `max` characters become identical, and their samples are merged.
+    /// Default is no limit.
+    pub fn max_symbol_length(self, max: usize) -> Self {
+        Self {
+            max_symbol_length: Some(max),
+            ..self
+        }
+    }
+
+    /// Keep the stacks of only the `max` most active threads (by sample
+    /// count) of a report. The stacks of all other threads are kept under a
+    /// single `[other threads]` root frame in place of their thread name, so
+    /// a workload spawning thousands of short-lived threads reports at most
+    /// `max + 1` threads. The number of threads gathered this way in the
+    /// last report is served by the status endpoint as `unsampled_threads`.
+    /// Default is no limit.
+    ///
+    /// Sampling itself is process-wide: every thread is still interrupted by
+    /// SIGPROF, the limit bounds the size of the reports and uploads.
+    pub fn max_profiled_threads(self, max: usize) -> Self {
+        Self {
+            max_profiled_threads: Some(max),
+            ..self
+        }
+    }
+
+    /// Set whether inlined functions are kept as frames of their own. The
+    /// debug information of an optimized build records the functions inlined
+    /// at every address: when true, each of them is a frame of the folded
+    /// stack, below the function it was inlined into, so the flamegraph shows
+    /// the call hierarchy of the source code. When false, only the function
+    /// the code was compiled into is kept, as in the machine stack: stacks
+    /// are shallower and the reports smaller, but the time of every inlined
+    /// function is attributed to its caller. Default is true.
+    ///
+    /// Inlined functions can only be expanded when the binary (or its
+    /// separate debug file) has debug information, e.g. `debug = 1` in the
+    /// release profile.
+    pub fn preserve_inlined(self, preserve_inlined: bool) -> Self {
+        Self {
+            preserve_inlined,
+            ..self
+        }
+    }
+
+    /// Apply the report processing options to the stacks
+    fn process(&mut self, mut stacks: Vec<(Stack, isize)>) -> Vec<(Stack, isize)> {
+        if let Some(max) = self.max_profiled_threads {
+            let (capped, unsampled_threads) = cap_threads(stacks, max);
+            stacks = capped;
+            self.unsampled_threads = unsampled_threads;
+        }
+
+        if let Some(max) = self.max_single_stack_depth {
+            stacks = truncate(stacks, max);
+        }
+
+        if !self.focus_prefixes.is_empty() {
+            stacks = focus(stacks, &self.focus_prefixes);
+        }
+
+        if let Some(max) = self.max_symbol_length {
+            stacks = shorten(stacks, max);
+        }
+
+        let stacks = merge_stacks(stacks);
+
+        match self.max_distinct_stacks {
+            Some(max) => cap_stacks(stacks, max),
+            None => stacks,
+        }
+    }
 }
 
 impl std::fmt::Debug for Pprof<'_> {
@@ -31,9 +219,7 @@ impl Backend for Pprof<'_> {
     fn initialize(&mut self, sample_rate: i32) -> Result<()> {
         // Check if Backend is Uninitialized
         if self.state != State::Uninitialized {
-            return Err(crate::error::PyroscopeError {
-                msg: String::from("Pprof Backend is already Initialized"),
-            });
+            return Err(PyroscopeError::new("Pprof Backend is already Initialized"));
         }
 
         // Construct a ProfilerGuardBuilder
@@ -50,13 +236,14 @@ impl Backend for Pprof<'_> {
     fn start(&mut self) -> Result<()> {
         // Check if Backend is Ready
         if self.state != State::Ready {
-            return Err(crate::error::PyroscopeError {
-                msg: String::from("Pprof Backend is not Ready"),
-            });
+            return Err(PyroscopeError::new("Pprof Backend is not Ready"));
         }
 
         self.guard = Some(self.inner_builder.as_ref().unwrap().clone().build()?);
 
+        // pprof installs its handler without SA_RESTART
+        set_restart_syscalls(self.restart_syscalls)?;
+
         // Set State to Running
         self.state = State::Running;
 
@@ -66,9 +253,7 @@ impl Backend for Pprof<'_> {
     fn stop(&mut self) -> Result<()> {
         // Check if Backend is Running
         if self.state != State::Running {
-            return Err(crate::error::PyroscopeError {
-                msg: String::from("Pprof Backend is not Running"),
-            });
+            return Err(PyroscopeError::new("Pprof Backend is not Running"));
         }
 
         // drop the guard
@@ -83,53 +268,382 @@ impl Backend for Pprof<'_> {
     fn report(&mut self) -> Result<Vec<u8>> {
         // Check if Backend is Running
         if self.state != State::Running {
-            return Err(crate::error::PyroscopeError {
-                msg: String::from("Pprof Backend is not Running"),
-            });
+            return Err(PyroscopeError::new("Pprof Backend is not Running"));
         }
 
         let mut buffer = Vec::new();
         let report = self.guard.as_ref().unwrap().report().build()?;
-        fold(&report, true, &mut buffer)?;
-
-        // Restart Profiler
-        self.stop()?;
-        self.start()?;
+        let stacks = stacks(&report, true, self.preserve_inlined);
+        let stacks = self.process(stacks);
+        fold(&stacks, &mut buffer)?;
 
         Ok(buffer)
     }
+
+    fn unsampled_threads(&self) -> usize {
+        self.unsampled_threads
+    }
 }
 
+/// Add or remove the SA_RESTART flag of the SIGPROF handler
+fn set_restart_syscalls(restart_syscalls: bool) -> Result<()> {
+    // Read the installed action
+    let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+    if unsafe { libc::sigaction(libc::SIGPROF, std::ptr::null(), &mut action) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    if restart_syscalls {
+        action.sa_flags |= libc::SA_RESTART;
+    } else {
+        action.sa_flags &= !libc::SA_RESTART;
+    }
+
+    // Install the updated action
+    if unsafe { libc::sigaction(libc::SIGPROF, &action, std::ptr::null_mut()) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+/// A folded stack: frame names from the root to the leaf
+type Stack = Vec<String>;
+
 // Copyright: https://github.com/YangKeao
-fn fold<W>(report: &Report, with_thread_name: bool, mut writer: W) -> Result<()>
-where W: std::io::Write {
-    for (key, value) in report.data.iter() {
-        if with_thread_name {
-            if !key.thread_name.is_empty() {
-                write!(writer, "{};", key.thread_name)?;
-            } else {
-                write!(writer, "{:?};", key.thread_id)?;
+/// Collect the stacks of a report. The thread name (or id) is the root frame
+/// of every stack when `with_thread_name` is set. Inlined functions are
+/// frames of their own when `preserve_inlined` is set.
+fn stacks(report: &Report, with_thread_name: bool, preserve_inlined: bool) -> Vec<(Stack, isize)> {
+    report
+        .data
+        .iter()
+        .map(|(key, value)| {
+            let mut stack = Vec::new();
+            if with_thread_name {
+                stack.push(key.thread_name_or_id());
+            }
+
+            for frame in key.frames.iter().rev() {
+                // Symbols of a frame go from the innermost inlined function
+                // to the function the code was compiled into
+                if preserve_inlined {
+                    for symbol in frame.iter().rev() {
+                        stack.push(symbol.to_string());
+                    }
+                } else if let Some(symbol) = frame.last() {
+                    stack.push(symbol.to_string());
+                }
             }
-        }
 
-        let last_frame = key.frames.len() - 1;
-        for (index, frame) in key.frames.iter().rev().enumerate() {
-            let last_symbol = frame.len() - 1;
-            for (index, symbol) in frame.iter().rev().enumerate() {
-                if index == last_symbol {
-                    write!(writer, "{}", symbol)?;
-                } else {
-                    write!(writer, "{};", symbol)?;
+            (stack, *value)
+        })
+        .collect()
+}
+
+/// Sum the counts of identical stacks. Stacks are sorted.
+fn merge_stacks(stacks: Vec<(Stack, isize)>) -> Vec<(Stack, isize)> {
+    let mut merged: BTreeMap<Stack, isize> = BTreeMap::new();
+    for (stack, count) in stacks {
+        *merged.entry(stack).or_insert(0) += count;
+    }
+
+    merged.into_iter().collect()
+}
+
+/// Keep only the frames starting with one of the prefixes. Every run of other
+/// frames is collapsed into a single EXTERNAL_FRAME. The root frame (thread
+/// name) is always kept.
+fn focus(stacks: Vec<(Stack, isize)>, prefixes: &[String]) -> Vec<(Stack, isize)> {
+    let focused = |frame: &String| {
+        // Trait implementations are rendered as `<path::Type as Trait>::fn`
+        let path = frame.strip_prefix('<').unwrap_or(frame);
+        prefixes
+            .iter()
+            .any(|prefix| path.starts_with(prefix.as_str()))
+    };
+
+    stacks
+        .into_iter()
+        .map(|(stack, count)| {
+            let mut frames = stack.into_iter();
+            let mut focused_stack: Stack = frames.next().into_iter().collect();
+            for frame in frames {
+                if focused(&frame) {
+                    focused_stack.push(frame);
+                } else if focused_stack.last().map(String::as_str) != Some(EXTERNAL_FRAME) {
+                    focused_stack.push(EXTERNAL_FRAME.to_owned());
                 }
             }
 
-            if index != last_frame {
-                write!(writer, ";")?;
+            (focused_stack, count)
+        })
+        .collect()
+}
+
+/// Truncate stacks with more than `max` frames below the root frame
+fn truncate(stacks: Vec<(Stack, isize)>, max: usize) -> Vec<(Stack, isize)> {
+    stacks
+        .into_iter()
+        .map(|(mut stack, count)| {
+            if stack.len() > max + 1 {
+                stack.truncate(max + 1);
+                stack.push(TRUNCATED_FRAME.to_owned());
             }
+
+            (stack, count)
+        })
+        .collect()
+}
+
+/// Shorten the frames longer than `max` characters below the root frame
+fn shorten(stacks: Vec<(Stack, isize)>, max: usize) -> Vec<(Stack, isize)> {
+    stacks
+        .into_iter()
+        .map(|(stack, count)| {
+            let stack = stack
+                .into_iter()
+                .enumerate()
+                .map(|(depth, frame)| match frame.char_indices().nth(max) {
+                    Some((end, _)) if depth > 0 => format!("{}{}", &frame[..end], SHORTENED_SYMBOL),
+                    _ => frame,
+                })
+                .collect();
+
+            (stack, count)
+        })
+        .collect()
+}
+
+/// Keep the `max` heaviest stacks and sum the others into OTHER_STACK
+fn cap_stacks(mut stacks: Vec<(Stack, isize)>, max: usize) -> Vec<(Stack, isize)> {
+    if stacks.len() <= max {
+        return stacks;
+    }
+
+    // Heaviest first, ties broken by stack
+    stacks.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then_with(|| a.cmp(b)));
+
+    let other: isize = stacks.drain(max..).map(|(_, count)| count).sum();
+    stacks.push((vec![OTHER_STACK.to_owned()], other));
+
+    stacks
+}
+
+/// Keep the `max` most active threads and move the stacks of the others
+/// under OTHER_THREADS. Returns the stacks and the number of moved threads.
+fn cap_threads(stacks: Vec<(Stack, isize)>, max: usize) -> (Vec<(Stack, isize)>, usize) {
+    let mut threads: BTreeMap<&str, isize> = BTreeMap::new();
+    for (stack, count) in &stacks {
+        if let Some(thread) = stack.first() {
+            *threads.entry(thread.as_str()).or_insert(0) += count;
         }
+    }
+
+    if threads.len() <= max {
+        return (stacks, 0);
+    }
 
-        writeln!(writer, " {}", value)?;
+    // Most active first, ties broken by thread name
+    let mut threads: Vec<(&str, isize)> = threads.into_iter().collect();
+    threads.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then_with(|| a.cmp(b)));
+    let kept: Vec<String> = threads[..max]
+        .iter()
+        .map(|(thread, _)| thread.to_string())
+        .collect();
+    let unsampled = threads.len() - max;
+
+    let stacks = stacks
+        .into_iter()
+        .map(|(mut stack, count)| {
+            if let Some(thread) = stack.first_mut() {
+                if !kept.contains(thread) {
+                    *thread = OTHER_THREADS.to_owned();
+                }
+            }
+
+            (stack, count)
+        })
+        .collect();
+
+    (stacks, unsampled)
+}
+
+/// Write stacks in the folded format
+fn fold<W>(stacks: &[(Stack, isize)], mut writer: W) -> Result<()>
+where W: std::io::Write {
+    for (stack, count) in stacks {
+        writeln!(writer, "{} {}", stack.join(";"), count)?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::backends::pprof::{
+        cap_stacks, cap_threads, focus, merge_stacks, shorten, stacks, truncate, Pprof, Stack,
+    };
+    use crate::backends::Backend;
+
+    /// Build a stack from `;` separated frames
+    fn stack(frames: &str) -> Stack {
+        frames.split(';').map(String::from).collect()
+    }
+
+    /// Read the flags of the SIGPROF handler
+    fn sigprof_flags() -> libc::c_int {
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        unsafe { libc::sigaction(libc::SIGPROF, std::ptr::null(), &mut action) };
+        action.sa_flags
+    }
+
+    #[test]
+    fn restart_syscalls() {
+        let mut backend = Pprof::default();
+        backend.initialize(100).unwrap();
+
+        backend.start().unwrap();
+        assert_ne!(sigprof_flags() & libc::SA_RESTART, 0);
+        backend.stop().unwrap();
+
+        let mut backend = Pprof::default().restart_syscalls(false);
+        backend.initialize(100).unwrap();
+
+        backend.start().unwrap();
+        assert_eq!(sigprof_flags() & libc::SA_RESTART, 0);
+        backend.stop().unwrap();
+    }
+
+    #[test]
+    fn focus_prefixes() {
+        let stacks = vec![
+            (
+                stack("main;std::rt::lang_start;myapp::main;tokio::run;tokio::poll;myapp::work"),
+                3,
+            ),
+            (
+                stack("main;std::rt::lang_start;myapp::main;<myapp::Job as Run>::run"),
+                2,
+            ),
+            (stack("main;std::rt::lang_start;core::ops::call"), 1),
+            (stack("worker;std::thread::start;core::ops::call"), 4),
+        ];
+
+        let focused = merge_stacks(focus(stacks, &["myapp::".to_string()]));
+
+        assert_eq!(focused, vec![
+            (stack("main;[external]"), 1),
+            (
+                stack("main;[external];myapp::main;<myapp::Job as Run>::run"),
+                2
+            ),
+            (
+                stack("main;[external];myapp::main;[external];myapp::work"),
+                3
+            ),
+            (stack("worker;[external]"), 4),
+        ]);
+    }
+
+    #[test]
+    fn max_distinct_stacks() {
+        let stacks = vec![
+            (stack("main;a"), 5),
+            (stack("main;b"), 1),
+            (stack("main;c"), 7),
+            (stack("main;d"), 2),
+        ];
+
+        assert_eq!(cap_stacks(stacks.clone(), 4), stacks);
+        assert_eq!(cap_stacks(stacks, 2), vec![
+            (stack("main;c"), 7),
+            (stack("main;a"), 5),
+            (stack("[other]"), 3)
+        ]);
+    }
+
+    #[test]
+    fn max_single_stack_depth() {
+        let stacks = vec![(stack("main;a;a;a;a;a"), 2), (stack("main;a;b"), 1)];
+
+        assert_eq!(truncate(stacks, 3), vec![
+            (stack("main;a;a;a;[truncated]"), 2),
+            (stack("main;a;b"), 1)
+        ]);
+    }
+
+    #[test]
+    fn max_symbol_length() {
+        let stacks = vec![
+            (
+                stack("main-thread;main;alloc::vec::Vec<(u8, u16)>::push"),
+                1,
+            ),
+            (
+                stack("main-thread;main;alloc::vec::Vec<(u8, u32)>::push"),
+                2,
+            ),
+        ];
+
+        assert_eq!(merge_stacks(shorten(stacks, 16)), vec![(
+            stack("main-thread;main;alloc::vec::Vec<..."),
+            3
+        )]);
+    }
+
+    #[test]
+    fn max_profiled_threads() {
+        let stacks = vec![
+            (stack("main;a"), 5),
+            (stack("worker-1;a"), 1),
+            (stack("worker-2;a"), 2),
+            (stack("worker-2;b"), 2),
+            (stack("worker-3;b"), 3),
+        ];
+
+        assert_eq!(cap_threads(stacks.clone(), 4), (stacks.clone(), 0));
+
+        let (capped, unsampled) = cap_threads(stacks, 2);
+        assert_eq!(unsampled, 2);
+        assert_eq!(merge_stacks(capped), vec![
+            (stack("[other threads];a"), 1),
+            (stack("[other threads];b"), 3),
+            (stack("main;a"), 5),
+            (stack("worker-2;a"), 2),
+            (stack("worker-2;b"), 2),
+        ]);
+    }
+
+    #[test]
+    fn preserve_inlined() {
+        let symbol = |name: &str| pprof::Symbol {
+            name: Some(name.as_bytes().to_vec()),
+            addr: None,
+            lineno: None,
+            filename: None,
+        };
+        // Frames go from the leaf to the root, and the symbols of a frame
+        // from the innermost inlined function
+        let frames = pprof::Frames {
+            frames: vec![vec![symbol("inlined_leaf"), symbol("work")], vec![symbol(
+                "main",
+            )]],
+            thread_name: String::from("main-thread"),
+            thread_id: 1,
+        };
+        let report = pprof::Report {
+            data: [(frames, 3)].into_iter().collect(),
+            timing: Default::default(),
+        };
+
+        assert_eq!(stacks(&report, true, true), vec![(
+            stack("main-thread;main;work;inlined_leaf"),
+            3
+        )]);
+        assert_eq!(stacks(&report, true, false), vec![(
+            stack("main-thread;main;work"),
+            3
+        )]);
+    }
+}
@@ -0,0 +1,182 @@
+// Copyright 2021 Developers of Pyroscope.
+
+// Licensed under the Apache License, Version 2.0 <LICENSE or
+// https://www.apache.org/licenses/LICENSE-2.0>. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Export folded reports in the Chrome Trace Event format, viewable in
+//! `chrome://tracing` or [Perfetto](https://ui.perfetto.dev).
+//!
+//! A folded report carries sample counts, not timestamps. The exporter lays
+//! out the stacks of every thread one after the other, sorted by name, as a
+//! flame chart: each sample lasts one sampling period (`1 / sample_rate`),
+//! and consecutive samples sharing a frame are merged into a single complete
+//! (`"ph": "X"`) event whose duration is the number of samples times the
+//! period. The time axis is therefore a sample count, not wall-clock time.
+//!
+//! The root frame of each stack is the thread name (as produced by the pprof
+//! backend) and becomes a thread of the trace, unless disabled with
+//! `with_thread_name(false)`.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::utils::{json_escape, parse_folded};
+use crate::Result;
+
+/// Chrome Trace Event exporter
+#[derive(Clone, Debug)]
+pub struct ChromeTraceExporter {
+    /// Sample rate used in Hz
+    sample_rate: u32,
+    /// Use the root frame as the thread name
+    with_thread_name: bool,
+}
+
+impl ChromeTraceExporter {
+    /// Create a new exporter for a report sampled at `sample_rate` Hz.
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate: sample_rate.max(1),
+            with_thread_name: true,
+        }
+    }
+
+    /// Set whether the root frame of each stack is the thread name. Default
+    /// is true.
+    pub fn with_thread_name(self, with_thread_name: bool) -> Self {
+        Self {
+            with_thread_name,
+            ..self
+        }
+    }
+
+    /// Export a folded report to a file.
+    pub fn export_to_file<P: AsRef<Path>>(&self, report: &[u8], path: P) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.export(report, &mut writer)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Export a folded report to a writer.
+    pub fn export<W: Write>(&self, report: &[u8], mut writer: W) -> Result<()> {
+        // Sampling period in microseconds
+        let period = 1_000_000 / u64::from(self.sample_rate);
+
+        // Group stacks by thread
+        let mut threads: BTreeMap<String, Vec<(Vec<String>, u64)>> = BTreeMap::new();
+        for (mut stack, count) in parse_folded(report)? {
+            let thread = if self.with_thread_name && !stack.is_empty() {
+                stack.remove(0)
+            } else {
+                String::from("main")
+            };
+            threads.entry(thread).or_default().push((stack, count));
+        }
+
+        write!(writer, "{{\"traceEvents\":[")?;
+        let mut first = true;
+        for (tid, (thread, mut stacks)) in threads.into_iter().enumerate() {
+            stacks.sort();
+
+            // Name the thread
+            write_event(
+                &mut writer,
+                &mut first,
+                &format!(
+                    "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":1,\"tid\":{},\"args\":{{\"name\":\"{}\"}}}}",
+                    tid,
+                    json_escape(&thread)
+                ),
+            )?;
+
+            // Open frames with their start time
+            let mut open: Vec<(&str, u64)> = Vec::new();
+            let mut cursor = 0u64;
+            for (stack, count) in &stacks {
+                // Close the frames not shared with this stack
+                let common = open
+                    .iter()
+                    .zip(stack.iter())
+                    .take_while(|((open, _), frame)| *open == frame.as_str())
+                    .count();
+                while open.len() > common {
+                    let (name, start) = open.pop().unwrap();
+                    write_complete(&mut writer, &mut first, name, start, cursor, tid)?;
+                }
+
+                // Open the new frames
+                for frame in &stack[common..] {
+                    open.push((frame, cursor));
+                }
+
+                cursor += count * period;
+            }
+            while let Some((name, start)) = open.pop() {
+                write_complete(&mut writer, &mut first, name, start, cursor, tid)?;
+            }
+        }
+        write!(writer, "],\"displayTimeUnit\":\"ms\"}}")?;
+
+        Ok(())
+    }
+}
+
+/// Write a complete event
+fn write_complete<W: Write>(
+    writer: &mut W, first: &mut bool, name: &str, start: u64, end: u64, tid: usize,
+) -> Result<()> {
+    write_event(
+        writer,
+        first,
+        &format!(
+            "{{\"name\":\"{}\",\"cat\":\"pyroscope\",\"ph\":\"X\",\"ts\":{},\"dur\":{},\"pid\":1,\"tid\":{}}}",
+            json_escape(name),
+            start,
+            end - start,
+            tid
+        ),
+    )
+}
+
+/// Write an event of the traceEvents array
+fn write_event<W: Write>(writer: &mut W, first: &mut bool, event: &str) -> Result<()> {
+    if !*first {
+        write!(writer, ",")?;
+    }
+    *first = false;
+    write!(writer, "{}", event)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chrome_trace::ChromeTraceExporter;
+
+    #[test]
+    fn export_folded_report() {
+        let report = b"main;a;b 2\nmain;a 1\nmain;a;c\"q 1\n";
+
+        let mut buffer = Vec::new();
+        ChromeTraceExporter::new(100)
+            .export(report, &mut buffer)
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(buffer).unwrap(),
+            concat!(
+                "{\"traceEvents\":[",
+                "{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":1,\"tid\":0,\"args\":{\"name\":\"main\"}},",
+                "{\"name\":\"b\",\"cat\":\"pyroscope\",\"ph\":\"X\",\"ts\":10000,\"dur\":20000,\"pid\":1,\"tid\":0},",
+                "{\"name\":\"c\\\"q\",\"cat\":\"pyroscope\",\"ph\":\"X\",\"ts\":30000,\"dur\":10000,\"pid\":1,\"tid\":0},",
+                "{\"name\":\"a\",\"cat\":\"pyroscope\",\"ph\":\"X\",\"ts\":0,\"dur\":40000,\"pid\":1,\"tid\":0}",
+                "],\"displayTimeUnit\":\"ms\"}"
+            )
+        );
+    }
+}
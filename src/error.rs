@@ -4,7 +4,6 @@
 // https://www.apache.org/licenses/LICENSE-2.0>. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::fmt;
 use thiserror::Error;
 
 /// Result Alias with PyroscopeError
@@ -12,60 +11,112 @@ pub type Result<T> = std::result::Result<T, PyroscopeError>;
 
 /// Error type of Pyroscope
 #[derive(Error, Debug)]
-pub struct PyroscopeError {
-    pub msg: String,
+pub enum PyroscopeError {
+    /// Generic error with a message
+    #[error("{0}")]
+    AdHoc(String),
+
+    /// Tag key outside of the allowed set
+    #[error("Tag key `{key}` is not allowed")]
+    DisallowedTag { key: String },
+
+    /// Application name empty or with characters Pyroscope does not allow
+    #[error("Invalid application name `{name}`")]
+    InvalidApplicationName { name: String },
+
+    /// Environment variable missing or with an invalid value
+    #[error("Environment variable `{name}` is missing or invalid")]
+    InvalidEnvVar { name: String },
+
+    /// Request that never reached the server (connection refused, DNS
+    /// failure or connect timeout): nothing was sent
+    #[error("Can't connect to the server")]
+    Connect,
+
+    /// Connection closed by the server before a response, once the request
+    /// was sent or while it was sent
+    #[error("Connection closed before a response")]
+    ConnectionClosed,
+
+    /// Request sent, but no response came in time
+    #[error("Request timed out")]
+    Timeout,
+
+    /// Request rejected by the server with a non-success status
+    #[error("Server responded with status {status}")]
+    HttpStatus { status: u16 },
+
+    /// Upload interval shorter than a second or with a fraction of a second
+    #[error("Invalid interval {interval:?}, must be a whole number of seconds")]
+    InvalidInterval { interval: std::time::Duration },
+
+    /// Custom environment that is not a valid or canonical tag value
+    #[error("Invalid environment `{value}`")]
+    InvalidEnvironment { value: String },
+
+    /// Tag key or value that can't be encoded in the application name
+    #[error("Invalid tag `{key}`")]
+    InvalidTag { key: String },
+
+    /// Report larger than the maximum payload size
+    #[error("Report of {size} bytes exceeds the maximum payload size of {max} bytes")]
+    PayloadTooLarge { size: usize, max: usize },
+
+    /// Tag key set with different values by two tag sources
+    #[error("Tag key `{key}` is set more than once")]
+    TagConflict { key: String },
 }
 
-impl fmt::Display for PyroscopeError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.msg)
+impl PyroscopeError {
+    /// Create a new generic error with a message
+    pub fn new(msg: &str) -> Self {
+        PyroscopeError::AdHoc(msg.to_owned())
     }
 }
 
+#[cfg(feature = "http")]
 impl From<reqwest::Error> for PyroscopeError {
-    fn from(_err: reqwest::Error) -> Self {
-        PyroscopeError {
-            msg: String::from("reqwest Error"),
+    fn from(err: reqwest::Error) -> Self {
+        // A connect timeout is a connection failure, nothing was sent
+        if err.is_connect() {
+            PyroscopeError::Connect
+        } else if err.is_timeout() {
+            PyroscopeError::Timeout
+        } else if err.is_request() {
+            PyroscopeError::ConnectionClosed
+        } else {
+            PyroscopeError::new("reqwest Error")
         }
     }
 }
 
+#[cfg(unix)]
 impl From<pprof::Error> for PyroscopeError {
     fn from(_err: pprof::Error) -> Self {
-        PyroscopeError {
-            msg: String::from("pprof Error"),
-        }
+        PyroscopeError::new("pprof Error")
     }
 }
 
 impl From<std::time::SystemTimeError> for PyroscopeError {
     fn from(_err: std::time::SystemTimeError) -> Self {
-        PyroscopeError {
-            msg: String::from("SystemTime Error"),
-        }
+        PyroscopeError::new("SystemTime Error")
     }
 }
 
 impl From<std::io::Error> for PyroscopeError {
     fn from(_err: std::io::Error) -> Self {
-        PyroscopeError {
-            msg: String::from("IO Error"),
-        }
+        PyroscopeError::new("IO Error")
     }
 }
 
 impl<T> From<std::sync::PoisonError<T>> for PyroscopeError {
     fn from(_err: std::sync::PoisonError<T>) -> Self {
-        PyroscopeError {
-            msg: String::from("Poison/Mutex Error"),
-        }
+        PyroscopeError::new("Poison/Mutex Error")
     }
 }
 
 impl<T> From<std::sync::mpsc::SendError<T>> for PyroscopeError {
     fn from(_err: std::sync::mpsc::SendError<T>) -> Self {
-        PyroscopeError {
-            msg: String::from("mpsc Send Error"),
-        }
+        PyroscopeError::new("mpsc Send Error")
     }
 }
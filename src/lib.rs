@@ -29,17 +29,66 @@
 //! ```ignore
 //!  agent.stop()?;
 //! ```
+//!
+//! ## Profiling startup
+//!
+//! The agent does not need an async runtime: sampling, the timer and the
+//! uploads all run on their own threads. To profile the startup of an
+//! application, start the agent first thing in a synchronous `main`, before
+//! building the runtime. The startup samples are uploaded with the first
+//! window, like any other.
+//!
+//! ```ignore
+//! fn main() -> Result<()> {
+//!     let mut agent = PyroscopeAgent::builder("http://localhost:4040", "myapp").build()?;
+//!     agent.start()?;
+//!
+//!     let runtime = tokio::runtime::Runtime::new()?;
+//!     runtime.block_on(run())
+//! }
+//! ```
+
+//! ## Sampling only
+//!
+//! The agent and its HTTP client (reqwest) are behind the default `http`
+//! feature. Without it (`default-features = false`), the crate is limited to
+//! sampling: a backend is driven directly and its folded reports are
+//! shipped by the application, e.g. with ProfileMerger.
+//!
+//! ```ignore
+//! let mut backend = Pprof::default();
+//! backend.initialize(100)?;
+//! backend.start()?;
+//! // Profiled computation
+//! let report = backend.report()?;
+//! backend.stop()?;
+//! ```
+//!
+//! The pprof backend samples with SIGPROF and is only available on Unix
+//! targets (Linux, macOS, BSDs); the `/proc` based tags are Linux only. The
+//! agent uses it as its default backend, so the `http` feature needs a Unix
+//! target too. Other targets, such as WASM, build without default features:
+//! they get the Backend trait, ProfileMerger and the Timer, and bring their
+//! own backend.
+
+#[cfg(all(feature = "http", not(unix)))]
+compile_error!("the `http` feature needs a Unix target, disable the default features");
 
 // Re-exports structs
-pub use crate::pyroscope::PyroscopeAgent;
+#[cfg(feature = "http")] pub use crate::pyroscope::PyroscopeAgent;
 pub use error::{PyroscopeError, Result};
 
 // Public modules
 pub mod backends;
 pub mod error;
-pub mod pyroscope;
-pub mod session;
+pub mod merger;
+#[cfg(feature = "http")] pub mod pyroscope;
+#[cfg(feature = "http")] pub mod retry;
+#[cfg(feature = "http")] pub mod session;
 pub mod timer;
 
+#[cfg(feature = "chrome-trace")] pub mod chrome_trace;
+
 // Private modules
+#[cfg(feature = "http")] mod status;
 mod utils;
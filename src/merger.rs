@@ -0,0 +1,129 @@
+// Copyright 2021 Developers of Pyroscope.
+
+// Licensed under the Apache License, Version 2.0 <LICENSE or
+// https://www.apache.org/licenses/LICENSE-2.0>. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Merge the profiles of several processes before upload.
+//!
+//! Short-lived child processes can profile themselves with a backend (see
+//! `Backend::report()`) and hand the result to a parent, which merges them
+//! and uploads a single profile.
+//!
+//! # Wire format
+//!
+//! A child profile is the folded output of a backend, sent as UTF-8 text:
+//! one `frame;frame;...;frame count` line per stack, terminated by `\n`.
+//! Several profiles can be written to the same pipe or socket one after the
+//! other; blank lines are ignored. The stream ends at EOF.
+//!
+//! ```ignore
+//! let mut merger = ProfileMerger::new();
+//! merger.read_from(child.stdout.take().unwrap())?;
+//!
+//! Session::new(until, config, merger.report())?.send()?;
+//! ```
+
+use std::collections::HashMap;
+use std::io::Read;
+
+use crate::utils::parse_folded;
+use crate::Result;
+
+/// Merge folded profiles, summing the counts of identical stacks
+#[derive(Clone, Debug, Default)]
+pub struct ProfileMerger {
+    /// Sample count of each stack
+    stacks: HashMap<String, u64>,
+}
+
+impl ProfileMerger {
+    /// Create an empty ProfileMerger
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a folded profile
+    pub fn add(&mut self, profile: &[u8]) -> Result<()> {
+        for (stack, count) in parse_folded(profile)? {
+            *self.stacks.entry(stack.join(";")).or_insert(0) += count;
+        }
+
+        Ok(())
+    }
+
+    /// Add one sample of `stack` (frames from the root to the leaf) with
+    /// `weight`, e.g. the time slept by a wall-clock sample in units of the
+    /// sampling period. Backends with weighted samples can accumulate them
+    /// here and return report() from Backend::report().
+    pub fn add_sample<S: AsRef<str>>(&mut self, stack: &[S], weight: u64) {
+        let stack = stack
+            .iter()
+            .map(|frame| frame.as_ref())
+            .collect::<Vec<&str>>()
+            .join(";");
+        *self.stacks.entry(stack).or_insert(0) += weight;
+    }
+
+    /// Read folded profiles until EOF and add them
+    pub fn read_from<R: Read>(&mut self, mut reader: R) -> Result<()> {
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer)?;
+
+        self.add(&buffer)
+    }
+
+    /// Return true if no stack was added
+    pub fn is_empty(&self) -> bool {
+        self.stacks.is_empty()
+    }
+
+    /// Return the merged profile in the folded format. Stacks are sorted.
+    pub fn report(&self) -> Vec<u8> {
+        let mut stacks: Vec<(&String, &u64)> = self.stacks.iter().collect();
+        stacks.sort();
+
+        stacks
+            .into_iter()
+            .map(|(stack, count)| format!("{} {}\n", stack, count))
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    /// Remove all stacks
+    pub fn clear(&mut self) {
+        self.stacks.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::merger::ProfileMerger;
+
+    #[test]
+    fn merge_profiles() {
+        let mut merger = ProfileMerger::new();
+        merger.add(b"main;a;b 2\nmain;a 1\n").unwrap();
+        merger.add(b"main;a;b 3\nmain;c 4\n").unwrap();
+        merger.read_from(&b"main;c 1\n\nmain;a 1\n"[..]).unwrap();
+
+        assert_eq!(
+            String::from_utf8(merger.report()).unwrap(),
+            "main;a 2\nmain;a;b 5\nmain;c 5\n"
+        );
+        assert!(merger.add(b"main;a\n").is_err());
+    }
+
+    #[test]
+    fn weighted_samples() {
+        let mut merger = ProfileMerger::new();
+        merger.add_sample(&["main", "sleep"], 30);
+        merger.add_sample(&["main", "work"], 1);
+        merger.add_sample(&["main", "sleep"], 12);
+
+        assert_eq!(
+            String::from_utf8(merger.report()).unwrap(),
+            "main;sleep 42\nmain;work 1\n"
+        );
+    }
+}
@@ -0,0 +1,182 @@
+// Copyright 2021 Developers of Pyroscope.
+
+// Licensed under the Apache License, Version 2.0 <LICENSE or
+// https://www.apache.org/licenses/LICENSE-2.0>. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Retry policies of the ingest requests
+//!
+//! Whether a failed upload is retried depends on where it failed, and on the
+//! RetrySafety of the agent:
+//!
+//! - Connection failures (connection refused, DNS failure, connect timeout)
+//!   happen before any byte of the request is sent. They are always retried.
+//! - 429 (Too Many Requests) responses: the server refused the request
+//!   without ingesting it. They are always retried.
+//! - 5xx responses, timeouts and connections closed after the request was
+//!   sent are ambiguous: the server may have ingested the window already,
+//!   and retrying may count it twice. They are retried only with
+//!   RetrySafety::Aggressive, the default.
+//! - Other 4xx statuses and any other error (e.g. an invalid response) are
+//!   permanent failures: the request is never retried.
+//!
+//! Before every retry, the agent asks the RetryPolicy for the delay to wait,
+//! or whether to give up. Uploads run in the background, so waiting does not
+//! delay the next window, but a policy should give up well before the end of
+//! the next window (10 seconds by default) to avoid piling up uploads.
+
+use std::fmt::Debug;
+use std::time::Duration;
+
+use crate::error::PyroscopeError;
+
+/// Failures of the ingest requests that are retried
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RetrySafety {
+    /// Retry only the requests that were not ingested, a window is never
+    /// counted twice by the server
+    Conservative,
+    /// Also retry the requests that may have been ingested: 5xx responses,
+    /// timeouts and connections closed after the request was sent (default)
+    #[default]
+    Aggressive,
+}
+
+/// Whether a failed ingest request may be retried, see the module
+/// documentation
+pub fn is_retryable(error: &PyroscopeError, safety: RetrySafety) -> bool {
+    match error {
+        PyroscopeError::Connect | PyroscopeError::HttpStatus { status: 429 } => true,
+        PyroscopeError::HttpStatus { status } if *status >= 500 => {
+            safety == RetrySafety::Aggressive
+        }
+        PyroscopeError::Timeout | PyroscopeError::ConnectionClosed => {
+            safety == RetrySafety::Aggressive
+        }
+        _ => false,
+    }
+}
+
+/// Retry Policy Trait
+pub trait RetryPolicy: Send + Sync + Debug {
+    /// Delay before the `attempt`th retry (starting at 1) of a request that
+    /// failed with `error`. Return None to give up.
+    fn next_delay(&self, attempt: u32, error: &PyroscopeError) -> Option<Duration>;
+}
+
+/// Retry with a delay doubling after every attempt
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExponentialBackoff {
+    /// Delay before the first retry
+    pub initial_delay: Duration,
+    /// Maximum delay between two attempts
+    pub max_delay: Duration,
+    /// Maximum number of retries
+    pub max_retries: u32,
+}
+
+impl Default for ExponentialBackoff {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(4),
+            max_retries: 3,
+        }
+    }
+}
+
+impl RetryPolicy for ExponentialBackoff {
+    fn next_delay(&self, attempt: u32, _error: &PyroscopeError) -> Option<Duration> {
+        if attempt == 0 || attempt > self.max_retries {
+            return None;
+        }
+
+        let delay = self
+            .initial_delay
+            .checked_mul(2u32.checked_pow(attempt - 1).unwrap_or(u32::MAX))
+            .unwrap_or(self.max_delay);
+
+        Some(delay.min(self.max_delay))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::error::PyroscopeError;
+    use crate::retry::{is_retryable, ExponentialBackoff, RetryPolicy, RetrySafety};
+
+    #[test]
+    fn exponential_backoff_delays() {
+        let policy = ExponentialBackoff {
+            max_retries: 40,
+            ..ExponentialBackoff::default()
+        };
+        let error = PyroscopeError::new("reqwest Error");
+
+        let delays = (1..=5)
+            .map(|attempt| policy.next_delay(attempt, &error).unwrap())
+            .collect::<Vec<Duration>>();
+        assert_eq!(delays, vec![
+            Duration::from_millis(500),
+            Duration::from_secs(1),
+            Duration::from_secs(2),
+            Duration::from_secs(4),
+            Duration::from_secs(4),
+        ]);
+        assert_eq!(policy.next_delay(40, &error), Some(Duration::from_secs(4)));
+        assert_eq!(policy.next_delay(41, &error), None);
+    }
+
+    #[test]
+    fn retryable_connection_failures() {
+        for safety in [RetrySafety::Conservative, RetrySafety::Aggressive] {
+            assert!(is_retryable(&PyroscopeError::Connect, safety));
+        }
+    }
+
+    #[test]
+    fn retryable_too_many_requests() {
+        for safety in [RetrySafety::Conservative, RetrySafety::Aggressive] {
+            assert!(is_retryable(
+                &PyroscopeError::HttpStatus { status: 429 },
+                safety
+            ));
+        }
+    }
+
+    #[test]
+    fn retryable_ambiguous_failures() {
+        for error in [
+            PyroscopeError::HttpStatus { status: 500 },
+            PyroscopeError::HttpStatus { status: 503 },
+            PyroscopeError::Timeout,
+            PyroscopeError::ConnectionClosed,
+        ] {
+            assert!(!is_retryable(&error, RetrySafety::Conservative));
+            assert!(is_retryable(&error, RetrySafety::Aggressive));
+        }
+    }
+
+    #[test]
+    fn permanent_failures() {
+        for safety in [RetrySafety::Conservative, RetrySafety::Aggressive] {
+            for status in [400, 401, 404, 413] {
+                assert!(!is_retryable(
+                    &PyroscopeError::HttpStatus { status },
+                    safety
+                ));
+            }
+            for error in [
+                PyroscopeError::new("reqwest Error"),
+                PyroscopeError::PayloadTooLarge { size: 2, max: 1 },
+                PyroscopeError::InvalidTag {
+                    key: String::from("region"),
+                },
+            ] {
+                assert!(!is_retryable(&error, safety));
+            }
+        }
+    }
+}
@@ -0,0 +1,379 @@
+// Copyright 2021 Developers of Pyroscope.
+
+// Licensed under the Apache License, Version 2.0 <LICENSE or
+// https://www.apache.org/licenses/LICENSE-2.0>. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{thread, thread::JoinHandle};
+
+use crate::backends::{Backend, State};
+use crate::pyroscope::{Counters, ProcAccess, PyroscopeConfig};
+use crate::session::Upload;
+use crate::utils::json_escape;
+use crate::Result;
+
+/// Outcome of the last upload
+#[derive(Clone, Debug)]
+struct LastUpload {
+    /// Unix time of the upload
+    time: u64,
+    /// Error message of a failed upload
+    error: Option<String>,
+}
+
+/// Agent status, shared between the agent, its threads and the status
+/// endpoint
+#[derive(Debug, Default)]
+pub(crate) struct AgentStatus {
+    /// Application name and tags of the running agent
+    name: Mutex<(String, HashMap<String, String>)>,
+    /// Number of reported windows
+    windows: AtomicU64,
+    /// Number of samples of the last window
+    samples: AtomicU64,
+    /// Number of successful uploads
+    uploads: AtomicU64,
+    /// Number of failed uploads
+    upload_errors: AtomicU64,
+    /// Number of retried ingest requests
+    retries: AtomicU64,
+    /// Number of bytes of the accepted reports
+    bytes_uploaded: AtomicU64,
+    /// Last upload
+    last_upload: Mutex<Option<LastUpload>>,
+    /// Files of /proc available to the enrichment features, once probed
+    proc_access: Mutex<Option<ProcAccess>>,
+}
+
+impl AgentStatus {
+    /// Record the name and tags the agent uploads with
+    pub(crate) fn set_config(&self, config: &PyroscopeConfig) -> Result<()> {
+        *self.name.lock()? = (config.application_name.clone(), config.tags.clone());
+
+        Ok(())
+    }
+
+    /// Record the result of the /proc probe
+    pub(crate) fn set_proc_access(&self, proc_access: ProcAccess) -> Result<()> {
+        *self.proc_access.lock()? = Some(proc_access);
+
+        Ok(())
+    }
+
+    /// Result of the /proc probe, None if it was not probed
+    pub(crate) fn proc_access(&self) -> Result<Option<ProcAccess>> {
+        Ok(*self.proc_access.lock()?)
+    }
+
+    /// Record a reported window
+    pub(crate) fn record_window(&self, report: &[u8]) {
+        self.windows.fetch_add(1, Ordering::Relaxed);
+        self.samples.store(count_samples(report), Ordering::Relaxed);
+    }
+
+    /// Record the outcome of an upload. Skipped windows did not make any
+    /// request and are not recorded.
+    pub(crate) fn record_upload(&self, result: &Result<Upload>) -> Result<()> {
+        let error = match result {
+            Ok(Upload::Skipped) => return Ok(()),
+            Ok(Upload::Sent) => {
+                self.uploads.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+            Err(err) => {
+                self.upload_errors.fetch_add(1, Ordering::Relaxed);
+                Some(err.to_string())
+            }
+        };
+
+        let time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+        *self.last_upload.lock()? = Some(LastUpload { time, error });
+
+        Ok(())
+    }
+
+    /// Record a retried ingest request
+    pub(crate) fn record_retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a report accepted by the server
+    pub(crate) fn record_bytes(&self, bytes: usize) {
+        self.bytes_uploaded
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Read the counters
+    pub(crate) fn counters(&self) -> Counters {
+        Counters {
+            windows: self.windows.load(Ordering::Relaxed),
+            uploads: self.uploads.load(Ordering::Relaxed),
+            upload_errors: self.upload_errors.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            bytes_uploaded: self.bytes_uploaded.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Reset the resettable counters, see Counters
+    pub(crate) fn reset_counters(&self) {
+        self.uploads.store(0, Ordering::Relaxed);
+        self.upload_errors.store(0, Ordering::Relaxed);
+        self.retries.store(0, Ordering::Relaxed);
+        self.bytes_uploaded.store(0, Ordering::Relaxed);
+    }
+
+    /// Render the status as JSON
+    fn to_json(&self, state: State, unsampled_threads: usize) -> Result<String> {
+        let (application_name, tags) = self.name.lock()?.clone();
+
+        let mut tags: Vec<(String, String)> = tags.into_iter().collect();
+        tags.sort();
+        let tags = tags
+            .iter()
+            .map(|(key, value)| format!("\"{}\":\"{}\"", json_escape(key), json_escape(value)))
+            .collect::<Vec<String>>()
+            .join(",");
+
+        let last_upload = match &*self.last_upload.lock()? {
+            Some(LastUpload { time, error: None }) => {
+                format!("{{\"time\":{},\"status\":\"ok\"}}", time)
+            }
+            Some(LastUpload {
+                time,
+                error: Some(error),
+            }) => format!(
+                "{{\"time\":{},\"status\":\"error\",\"error\":\"{}\"}}",
+                time,
+                json_escape(error)
+            ),
+            None => String::from("null"),
+        };
+
+        let proc_access = match *self.proc_access.lock()? {
+            Some(access) => format!(
+                "{{\"statm\":{},\"loadavg\":{},\"status\":{}}}",
+                access.statm, access.loadavg, access.status
+            ),
+            None => String::from("null"),
+        };
+
+        let state = match state {
+            State::Uninitialized => "uninitialized",
+            State::Ready => "ready",
+            State::Running => "running",
+        };
+
+        Ok(format!(
+            "{{\"state\":\"{}\",\"application_name\":\"{}\",\"tags\":{{{}}},\"windows\":{},\"samples\":{},\"unsampled_threads\":{},\"uploads\":{},\"upload_errors\":{},\"retries\":{},\"bytes_uploaded\":{},\"proc_access\":{},\"last_upload\":{}}}",
+            state,
+            json_escape(&application_name),
+            tags,
+            self.windows.load(Ordering::Relaxed),
+            self.samples.load(Ordering::Relaxed),
+            unsampled_threads,
+            self.uploads.load(Ordering::Relaxed),
+            self.upload_errors.load(Ordering::Relaxed),
+            self.retries.load(Ordering::Relaxed),
+            self.bytes_uploaded.load(Ordering::Relaxed),
+            proc_access,
+            last_upload
+        ))
+    }
+}
+
+/// Sum the sample counts of a folded report
+fn count_samples(report: &[u8]) -> u64 {
+    String::from_utf8_lossy(report)
+        .lines()
+        .filter_map(|line| line.rsplit_once(' '))
+        .filter_map(|(_, count)| count.trim().parse::<u64>().ok())
+        .sum()
+}
+
+/// Read-only HTTP endpoint serving the agent status as JSON
+#[derive(Debug)]
+pub(crate) struct StatusServer {
+    /// Local address of the endpoint
+    addr: SocketAddr,
+    /// Shutdown flag
+    shutdown: Arc<AtomicBool>,
+    /// Thread handle
+    handle: Option<JoinHandle<()>>,
+}
+
+impl StatusServer {
+    /// Bind `addr` and serve the status from a new thread
+    pub(crate) fn start(
+        addr: SocketAddr, status: Arc<AgentStatus>, backend: Arc<Mutex<dyn Backend>>,
+    ) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        // Poll accept() so the thread can notice the shutdown flag
+        listener.set_nonblocking(true)?;
+
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&shutdown);
+
+        let handle = thread::Builder::new()
+            .name(String::from("pyroscope-status"))
+            .spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    match listener.accept() {
+                        Ok((stream, _)) => {
+                            // Errors only affect this client
+                            let _ = respond(stream, &status, &backend);
+                        }
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                            thread::sleep(Duration::from_millis(50));
+                        }
+                        Err(_) => thread::sleep(Duration::from_millis(50)),
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            addr,
+            shutdown,
+            handle: Some(handle),
+        })
+    }
+
+    /// Local address of the endpoint
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for StatusServer {
+    /// Stop the endpoint thread
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Answer a single request
+fn respond(
+    mut stream: TcpStream, status: &AgentStatus, backend: &Arc<Mutex<dyn Backend>>,
+) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+    // Only the request line matters
+    let mut buffer = [0u8; 1024];
+    let n = stream.read(&mut buffer)?;
+    let request = String::from_utf8_lossy(&buffer[..n]);
+
+    let (status_line, body) = if request.starts_with("GET ") {
+        let backend = backend.lock()?;
+        let body = status.to_json(backend.get_state(), backend.unsampled_threads())?;
+        ("200 OK", body)
+    } else {
+        ("405 Method Not Allowed", String::from("{}"))
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status_line,
+        body.len(),
+        body
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::{Arc, Mutex};
+
+    use crate::backends::pprof::Pprof;
+    use crate::pyroscope::{Counters, ProcAccess, PyroscopeConfig};
+    use crate::session::Upload;
+    use crate::status::{AgentStatus, StatusServer};
+    use crate::PyroscopeError;
+
+    #[test]
+    fn serve_status() {
+        let status = Arc::new(AgentStatus::default());
+        status
+            .set_config(
+                &PyroscopeConfig::new("http://localhost:4040", "app").tags(&[("env", "ci")]),
+            )
+            .unwrap();
+        status.record_window(b"main;a 2\nmain;b 3\n");
+        status
+            .set_proc_access(ProcAccess {
+                statm: true,
+                loadavg: false,
+                status: true,
+            })
+            .unwrap();
+        status.record_upload(&Ok(Upload::Sent)).unwrap();
+        status
+            .record_upload(&Err(PyroscopeError::new("reqwest Error")))
+            .unwrap();
+        status.record_upload(&Ok(Upload::Skipped)).unwrap();
+        status.record_retry();
+        status.record_bytes(18);
+
+        let server = StatusServer::start(
+            "127.0.0.1:0".parse().unwrap(),
+            Arc::clone(&status),
+            Arc::new(Mutex::new(Pprof::default())),
+        )
+        .unwrap();
+
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        write!(stream, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        assert!(body.starts_with(
+            "{\"state\":\"uninitialized\",\"application_name\":\"app\",\"tags\":{\"env\":\"ci\"},\"windows\":1,\"samples\":5,\"unsampled_threads\":0,\"uploads\":1,\"upload_errors\":1,\"retries\":1,\"bytes_uploaded\":18,\"proc_access\":{\"statm\":true,\"loadavg\":false,\"status\":true},\"last_upload\":{\"time\":"
+        ));
+        assert!(body.ends_with(",\"status\":\"error\",\"error\":\"reqwest Error\"}}"));
+    }
+
+    #[test]
+    fn counters() {
+        let status = AgentStatus::default();
+        status.record_window(b"main;a 2\n");
+        status.record_upload(&Ok(Upload::Sent)).unwrap();
+        status
+            .record_upload(&Err(PyroscopeError::new("reqwest Error")))
+            .unwrap();
+        status.record_upload(&Ok(Upload::Skipped)).unwrap();
+        status.record_retry();
+        status.record_bytes(9);
+
+        assert_eq!(status.counters(), Counters {
+            windows: 1,
+            uploads: 1,
+            upload_errors: 1,
+            retries: 1,
+            bytes_uploaded: 9,
+        });
+
+        // The window count is monotonic
+        status.reset_counters();
+        assert_eq!(status.counters(), Counters {
+            windows: 1,
+            ..Counters::default()
+        });
+    }
+}
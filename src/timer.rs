@@ -6,66 +6,149 @@
 
 use crate::Result;
 
-use std::sync::{mpsc::Sender, Arc, Mutex};
+use std::sync::{mpsc::Sender, Arc, Condvar, Mutex};
 use std::time::Duration;
 use std::{thread, thread::JoinHandle};
 
+/// Behavior of the Timer when events were missed
+///
+/// The Timer thread can wake up long after an event was due, most notably
+/// when the system was suspended or the VM paused: sleeping does not advance
+/// while the system is suspended, so every event scheduled during the
+/// suspension is missed at once.
+///
+/// - `Burst` sends every missed event immediately, with its own timestamp.
+///   Nothing is skipped, but listeners receive a flood of events on resume,
+///   and every upload but the last covers a window with no samples.
+/// - `Delay` sends a single event immediately, with the current timestamp,
+///   and schedules the next one a full interval later. Events stop being
+///   aligned on multiples of the interval, so the windows of agents no longer
+///   line up.
+/// - `Skip` sends a single event for the most recent missed multiple of the
+///   interval and keeps the schedule aligned. The missed windows are merged
+///   into one.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MissedTickBehavior {
+    /// Send every missed event
+    Burst,
+    /// Send one event and start a new schedule from now
+    Delay,
+    /// Send one event and keep the schedule (default)
+    #[default]
+    Skip,
+}
+
+/// Events due at `now` (in seconds) for an event scheduled at `scheduled`,
+/// and the time of the next event, `interval` seconds apart
+fn due_events(
+    behavior: MissedTickBehavior, interval: u64, scheduled: u64, now: u64,
+) -> (Vec<u64>, u64) {
+    if now < scheduled {
+        return (Vec::new(), scheduled);
+    }
+
+    match behavior {
+        MissedTickBehavior::Burst => {
+            let events = (scheduled..=now)
+                .step_by(interval as usize)
+                .collect::<Vec<u64>>();
+            let next = events.last().unwrap() + interval;
+            (events, next)
+        }
+        MissedTickBehavior::Delay => (vec![now], now + interval),
+        MissedTickBehavior::Skip => {
+            let last = now - (now - scheduled) % interval;
+            (vec![last], last + interval)
+        }
+    }
+}
+
 /// A thread that sends a notification every 10th second
 ///
 /// Timer will send an event to attached listeners (mpsc::Sender) every 10th
-/// second (...10, ...20, ...)
+/// second (...10, ...20, ...), or on every multiple of the configured
+/// interval.
 ///
-/// The Timer thread will run continously until all Senders are dropped.
+/// The Timer thread will run continously until the listeners are dropped with
+/// drop_listeners().
 
 #[derive(Debug, Default)]
 pub struct Timer {
     /// A vector to store listeners (mpsc::Sender)
     txs: Arc<Mutex<Vec<Sender<u64>>>>,
 
+    /// Behavior when events were missed
+    missed_tick_behavior: MissedTickBehavior,
+
+    /// Interval between two events. 10 seconds when unset.
+    interval: Option<Duration>,
+
+    /// Shutdown flag, notified by drop_listeners()
+    shutdown: Arc<(Mutex<bool>, Condvar)>,
+
     /// Thread handle
     pub handle: Option<JoinHandle<Result<()>>>,
 }
 
 impl Timer {
+    /// Set the behavior when events were missed. Default is Skip.
+    pub fn missed_tick_behavior(self, missed_tick_behavior: MissedTickBehavior) -> Self {
+        Self {
+            missed_tick_behavior,
+            ..self
+        }
+    }
+
+    /// Set the interval between two events, in whole seconds. Default is 10
+    /// seconds.
+    pub fn interval(self, interval: Duration) -> Self {
+        Self {
+            interval: Some(interval),
+            ..self
+        }
+    }
+
     /// Initialize Timer and run a thread to send events to attached listeners
     pub fn initialize(self) -> Self {
         let txs = Arc::clone(&self.txs);
-
-        // Add tx
-        // txs.lock().unwrap().push(tx);
+        let behavior = self.missed_tick_behavior;
+        let interval = self
+            .interval
+            .map_or(10, |interval| interval.as_secs())
+            .max(1);
+        let shutdown = Arc::clone(&self.shutdown);
 
         // Spawn a Thread
         let handle = Some(thread::spawn(move || {
             // Get the current time
-            let now = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)?
-                .as_secs();
+            let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
 
-            // Calculate number of seconds until 10th second
-            let rem = 10u64.checked_sub(now.checked_rem(10).unwrap()).unwrap();
-
-            // Sleep for rem seconds
-            thread::sleep(Duration::from_secs(rem));
+            // Schedule the first event on the next multiple of the interval
+            let mut scheduled = now.as_secs() - now.as_secs() % interval + interval;
 
             loop {
-                // Exit thread if there are no listeners
-                if txs.lock()?.len() == 0 {
-                    return Ok(());
+                // Sleep until the next event, or exit thread if the
+                // listeners were dropped
+                let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+                let next = Duration::from_secs(scheduled);
+                if now < next {
+                    let (lock, cvar) = &*shutdown;
+                    let (stopped, _) =
+                        cvar.wait_timeout_while(lock.lock()?, next - now, |stopped| !*stopped)?;
+                    if *stopped {
+                        return Ok(());
+                    }
+                    continue;
                 }
 
-                // Get current time
-                let current = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)?
-                    .as_secs();
-
-                // Iterate through Senders
-                txs.lock()?.iter().for_each(|tx| {
-                    // Send event to attached Sender
-                    tx.send(current).unwrap();
-                });
+                let (events, next) = due_events(behavior, interval, scheduled, now.as_secs());
+                scheduled = next;
 
-                // Sleep for 10s
-                thread::sleep(Duration::from_millis(10000));
+                // Send events to attached Senders. Senders whose receiver was
+                // dropped are removed.
+                for event in events {
+                    txs.lock()?.retain(|tx| tx.send(event).is_ok());
+                }
             }
         }));
 
@@ -75,7 +158,7 @@ impl Timer {
     /// Attach an mpsc::Sender to Timer
     ///
     /// Timer will dispatch an event with the timestamp of the current instant,
-    /// every 10th second to all attached senders
+    /// every 10th second (or interval) to all attached senders
     pub fn attach_listener(&mut self, tx: Sender<u64>) -> Result<()> {
         // Push Sender to a Vector of Sender(s)
         let txs = Arc::clone(&self.txs);
@@ -89,6 +172,69 @@ impl Timer {
         let txs = Arc::clone(&self.txs);
         txs.lock()?.clear();
 
+        // Wake up the Timer thread
+        let (lock, cvar) = &*self.shutdown;
+        *lock.lock()? = true;
+        cvar.notify_one();
+
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::timer::{due_events, MissedTickBehavior, Timer};
+
+    #[test]
+    fn drop_listeners_shutdown() {
+        let mut timer = Timer::default().initialize();
+        let start = Instant::now();
+
+        timer.drop_listeners().unwrap();
+        timer.handle.take().unwrap().join().unwrap().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn due_events_on_time() {
+        for behavior in [
+            MissedTickBehavior::Burst,
+            MissedTickBehavior::Delay,
+            MissedTickBehavior::Skip,
+        ] {
+            assert_eq!(due_events(behavior, 10, 20, 15), (vec![], 20));
+            assert_eq!(due_events(behavior, 10, 20, 20), (vec![20], 30));
+        }
+    }
+
+    #[test]
+    fn due_events_interval() {
+        assert_eq!(
+            due_events(MissedTickBehavior::Burst, 30, 30, 95),
+            (vec![30, 60, 90], 120)
+        );
+        assert_eq!(
+            due_events(MissedTickBehavior::Skip, 5, 20, 33),
+            (vec![30], 35)
+        );
+    }
+
+    #[test]
+    fn due_events_after_stall() {
+        // Woke up at 75 for an event scheduled at 20
+        assert_eq!(
+            due_events(MissedTickBehavior::Burst, 10, 20, 75),
+            (vec![20, 30, 40, 50, 60, 70], 80)
+        );
+        assert_eq!(
+            due_events(MissedTickBehavior::Delay, 10, 20, 75),
+            (vec![75], 85)
+        );
+        assert_eq!(
+            due_events(MissedTickBehavior::Skip, 10, 20, 75),
+            (vec![70], 80)
+        );
+    }
+}